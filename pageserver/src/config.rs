@@ -0,0 +1,93 @@
+//!
+//! `PageServerConf` holds the settings for one pageserver process, loaded
+//! from its config file with command-line overrides layered on top.
+//!
+//! This module only carries the fields that `layered_repository` actually
+//! consults (`compress_layers`, `compress_level`, `remote_storage`, and the
+//! `workdir` that `timeline_path` is derived from). The rest of the page
+//! server's configuration (listen addresses, auth, WAL redo, ...) lives
+//! alongside it in the full pageserver crate.
+//!
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::ZTimelineId;
+
+/// Settings for one pageserver process.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PageServerConf {
+    /// Root directory under which every tenant's timelines are stored.
+    pub workdir: PathBuf,
+
+    /// Whether newly written layer files should be zstd-compressed.
+    ///
+    /// Defaults to `false`, because `SnapshotLayer::serialize_page_versions`
+    /// can only write the footer-indexed, mmap-lazy format (see chunk1-5)
+    /// when this is `false` -- a compressed buffer can't be sought into
+    /// entry by entry, so a compressed layer always falls back to slurping
+    /// and deserializing the whole file on `load`. Pass `--compress-layers`
+    /// on the command line (or set `compress_layers = true` in the config
+    /// file) to trade that lazy-loading benefit for disk space, e.g. for
+    /// layers expected to sit cold in remote storage rather than be read
+    /// hot off local disk.
+    pub compress_layers: bool,
+
+    /// zstd compression level to use when `compress_layers` is set.
+    /// `None` (the default) means "use zstd's own default level".
+    pub compress_level: Option<i32>,
+
+    /// Object-storage bucket for layer files. When set, every layer of
+    /// every timeline is read from and written to this bucket instead of
+    /// local disk; layers are not split between local disk and the bucket,
+    /// and nothing evicts layers from one to the other. `None` (the
+    /// default) means every layer stays on local disk, the behavior before
+    /// this setting existed.
+    pub remote_storage: Option<RemoteStorageConfig>,
+}
+
+/// Which object-storage backend `layer_storage::storage_for` should route
+/// to, and how to reach it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteStorageConfig {
+    pub bucket_name: String,
+    pub bucket_region: String,
+
+    /// Key prefix under which this pageserver's layers live in the bucket,
+    /// so one bucket can be shared by several pageservers or tenants.
+    #[serde(default)]
+    pub prefix_in_bucket: Option<String>,
+}
+
+impl Default for PageServerConf {
+    fn default() -> Self {
+        PageServerConf {
+            workdir: PathBuf::from("."),
+            compress_layers: false,
+            compress_level: None,
+            remote_storage: None,
+        }
+    }
+}
+
+impl PageServerConf {
+    /// Apply the subset of command-line flags that override layer storage
+    /// settings. Called after the config file has been parsed into a
+    /// `PageServerConf` via its `Deserialize` impl, so flags that were
+    /// actually passed win over whatever the config file said.
+    pub fn apply_compress_overrides(&mut self, compress_layers: bool, compress_level: Option<i32>) {
+        if compress_layers {
+            self.compress_layers = true;
+        }
+        if compress_level.is_some() {
+            self.compress_level = compress_level;
+        }
+    }
+
+    /// The directory a timeline's layer files are stored under.
+    pub fn timeline_path(&self, timelineid: ZTimelineId) -> PathBuf {
+        self.workdir.join("timelines").join(timelineid.to_string())
+    }
+}
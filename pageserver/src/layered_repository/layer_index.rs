@@ -0,0 +1,325 @@
+//!
+//! A lazily-built, cached index of each timeline's on-disk snapshot and
+//! delta layers, so that `SnapshotLayer::find_latest_snapshot_file`,
+//! `::list_rels` and `find_latest_delta_layer` don't have to list and parse
+//! every layer filename on every lookup.
+//!
+//! The cache is keyed by timeline and invalidated by a per-timeline
+//! generation counter: whenever `SnapshotLayer::create` or `::compact`, or
+//! `DeltaLayer::create`, adds or removes a layer file, it calls
+//! `advance_generation`. A cached
+//! index whose generation doesn't match the timeline's current generation
+//! is stale and gets rebuilt from a fresh directory scan; otherwise the
+//! lookup is just a map probe.
+//!
+//! Both maps are keyed by `ZTimelineId` and never shrink on their own: a
+//! timeline that's deleted needs `forget_timeline` called on it, or its
+//! entry lingers in both maps for the rest of the process's life.
+//!
+
+use crate::layered_repository::delta_layer::DeltaLayer;
+use crate::layered_repository::layer_storage;
+use crate::layered_repository::layer_storage::LayerStorage;
+use crate::layered_repository::snapshot_layer::SnapshotLayer;
+use crate::repository::RelTag;
+use crate::PageServerConf;
+use crate::ZTimelineId;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use zenith_utils::lsn::Lsn;
+
+/// The layers known for each `RelTag` in one timeline, as of `generation`.
+struct CachedIndex {
+    generation: u64,
+    by_tag: BTreeMap<RelTag, Vec<(Lsn, Lsn)>>,
+    delta_by_tag: BTreeMap<RelTag, Vec<(Lsn, Lsn)>>,
+}
+
+fn generations() -> &'static Mutex<BTreeMap<ZTimelineId, AtomicU64>> {
+    static GENERATIONS: OnceLock<Mutex<BTreeMap<ZTimelineId, AtomicU64>>> = OnceLock::new();
+    GENERATIONS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+fn cache() -> &'static Mutex<BTreeMap<ZTimelineId, CachedIndex>> {
+    static CACHE: OnceLock<Mutex<BTreeMap<ZTimelineId, CachedIndex>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+fn current_generation(timelineid: ZTimelineId) -> u64 {
+    generations()
+        .lock()
+        .unwrap()
+        .entry(timelineid)
+        .or_insert_with(|| AtomicU64::new(0))
+        .load(Ordering::SeqCst)
+}
+
+/// Tell the index that `timelineid`'s on-disk layers changed (a layer was
+/// added or removed), so the next lookup rebuilds its cached scan instead
+/// of trusting stale data.
+pub fn advance_generation(timelineid: ZTimelineId) {
+    generations()
+        .lock()
+        .unwrap()
+        .entry(timelineid)
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(1, Ordering::SeqCst);
+}
+
+/// Remove `timelineid`'s entries from both the generation counter and the
+/// cached index. Must be called when a timeline is deleted; otherwise a
+/// page server that churns through many short-lived branch timelines over
+/// its process lifetime grows these maps without bound, since nothing else
+/// ever removes an entry once inserted.
+pub fn forget_timeline(timelineid: ZTimelineId) {
+    generations().lock().unwrap().remove(&timelineid);
+    cache().lock().unwrap().remove(&timelineid);
+}
+
+/// Every known snapshot and delta layer's LSN range for `timelineid`,
+/// grouped by `RelTag`. Scans the timeline's directory only if there's no
+/// cached index still matching its current generation.
+fn layers_by_tag(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+) -> Result<(
+    BTreeMap<RelTag, Vec<(Lsn, Lsn)>>,
+    BTreeMap<RelTag, Vec<(Lsn, Lsn)>>,
+)> {
+    let generation = current_generation(timelineid);
+
+    {
+        let cache = cache().lock().unwrap();
+        if let Some(cached) = cache.get(&timelineid) {
+            if cached.generation == generation {
+                return Ok((cached.by_tag.clone(), cached.delta_by_tag.clone()));
+            }
+        }
+    }
+
+    let storage = layer_storage::storage_for(conf, timelineid);
+    let mut by_tag: BTreeMap<RelTag, Vec<(Lsn, Lsn)>> = BTreeMap::new();
+    let mut delta_by_tag: BTreeMap<RelTag, Vec<(Lsn, Lsn)>> = BTreeMap::new();
+    for entry in storage.list(Path::new(""))? {
+        let fname = entry.to_str().unwrap();
+        if let Some((reltag, start_lsn, end_lsn)) = SnapshotLayer::fname_to_tag(fname) {
+            by_tag.entry(reltag).or_default().push((start_lsn, end_lsn));
+        } else if let Some((reltag, start_lsn, end_lsn)) = DeltaLayer::fname_to_tag(fname) {
+            delta_by_tag
+                .entry(reltag)
+                .or_default()
+                .push((start_lsn, end_lsn));
+        }
+    }
+
+    cache().lock().unwrap().insert(
+        timelineid,
+        CachedIndex {
+            generation,
+            by_tag: by_tag.clone(),
+            delta_by_tag: delta_by_tag.clone(),
+        },
+    );
+
+    Ok((by_tag, delta_by_tag))
+}
+
+fn latest_covering(ranges: &[(Lsn, Lsn)], lsn: Lsn) -> Option<(Lsn, Lsn)> {
+    let mut result: Option<(Lsn, Lsn)> = None;
+    for &(start_lsn, end_lsn) in ranges {
+        if start_lsn <= lsn && result.map_or(true, |(result_start, _)| start_lsn > result_start) {
+            result = Some((start_lsn, end_lsn));
+        }
+    }
+    result
+}
+
+/// Find the snapshot file with latest LSN that covers the given `lsn`, or
+/// is before it.
+pub fn find_latest_snapshot_file(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tag: RelTag,
+    lsn: Lsn,
+) -> Result<Option<(Lsn, Lsn)>> {
+    let (by_tag, _delta_by_tag) = layers_by_tag(conf, timelineid)?;
+    Ok(by_tag.get(&tag).and_then(|ranges| latest_covering(ranges, lsn)))
+}
+
+/// Find the delta layer with the latest LSN range that covers the given
+/// `lsn`, or is before it. This is the delta-layer counterpart of
+/// `find_latest_snapshot_file`, used by `layer_map` to pick where to start
+/// walking the reconstruction chain.
+pub fn find_latest_delta_layer(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tag: RelTag,
+    lsn: Lsn,
+) -> Result<Option<(Lsn, Lsn)>> {
+    let (_by_tag, delta_by_tag) = layers_by_tag(conf, timelineid)?;
+    Ok(delta_by_tag
+        .get(&tag)
+        .and_then(|ranges| latest_covering(ranges, lsn)))
+}
+
+/// All distinct `RelTag`s with at least one snapshot layer in `timelineid`
+/// (delta layers are not counted), matching
+/// `spcnode`/`dbnode` if given (0 means "any").
+pub fn list_rels(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    spcnode: u32,
+    dbnode: u32,
+) -> Result<HashSet<RelTag>> {
+    let (by_tag, _delta_by_tag) = layers_by_tag(conf, timelineid)?;
+    Ok(by_tag
+        .keys()
+        .filter(|reltag| {
+            (spcnode == 0 || reltag.spcnode == spcnode) && (dbnode == 0 || reltag.dbnode == dbnode)
+        })
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PageServerConf;
+
+    #[test]
+    fn advance_generation_bumps_the_counter_for_that_timeline_only() {
+        let a = ZTimelineId::generate();
+        let b = ZTimelineId::generate();
+
+        let before_a = current_generation(a);
+        let before_b = current_generation(b);
+
+        advance_generation(a);
+
+        assert_eq!(current_generation(a), before_a + 1);
+        assert_eq!(current_generation(b), before_b);
+    }
+
+    #[test]
+    fn forget_timeline_evicts_the_generation_counter_and_cached_index() {
+        let timelineid = ZTimelineId::generate();
+        let mut conf = PageServerConf::default();
+        conf.workdir = std::env::temp_dir().join(format!("pageserver-layer-index-test-{}", timelineid));
+        let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+
+        let timeline_dir = conf.timeline_path(timelineid);
+        std::fs::create_dir_all(&timeline_dir).unwrap();
+
+        // Populate both the generation counter and the cached index.
+        advance_generation(timelineid);
+        list_rels(conf, timelineid, 0, 0).unwrap();
+        assert!(cache().lock().unwrap().contains_key(&timelineid));
+
+        forget_timeline(timelineid);
+
+        assert!(!generations().lock().unwrap().contains_key(&timelineid));
+        assert!(!cache().lock().unwrap().contains_key(&timelineid));
+        // Forgotten timelines start back over at generation 0, same as one
+        // that's never been seen before.
+        assert_eq!(current_generation(timelineid), 0);
+
+        std::fs::remove_dir_all(&conf.workdir).ok();
+    }
+
+    #[test]
+    fn layers_by_tag_cache_is_invalidated_by_advance_generation() {
+        let timelineid = ZTimelineId::generate();
+        let mut conf = PageServerConf::default();
+        conf.workdir = std::env::temp_dir().join(format!("pageserver-layer-index-test-{}", timelineid));
+        let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+
+        let timeline_dir = conf.timeline_path(timelineid);
+        std::fs::create_dir_all(&timeline_dir).unwrap();
+
+        let tag = RelTag {
+            spcnode: 1,
+            dbnode: 2,
+            relnode: 3,
+            forknum: 0,
+        };
+        let fname_for = |start: u64, end: u64| {
+            format!(
+                "{}_{}_{}_{}_{:016X}_{:016X}",
+                tag.spcnode, tag.dbnode, tag.relnode, tag.forknum, start, end
+            )
+        };
+        std::fs::write(timeline_dir.join(fname_for(100, 200)), b"").unwrap();
+
+        let found = find_latest_snapshot_file(conf, timelineid, tag, Lsn(150)).unwrap();
+        assert_eq!(found, Some((Lsn(100), Lsn(200))));
+
+        // Add a second, newer layer directly on disk without advancing the
+        // generation: the cached scan must not pick it up yet.
+        std::fs::write(timeline_dir.join(fname_for(200, 300)), b"").unwrap();
+        let stale = find_latest_snapshot_file(conf, timelineid, tag, Lsn(250)).unwrap();
+        assert_eq!(
+            stale,
+            Some((Lsn(100), Lsn(200))),
+            "cached index shouldn't see the new file before the generation advances"
+        );
+
+        advance_generation(timelineid);
+
+        let fresh = find_latest_snapshot_file(conf, timelineid, tag, Lsn(250)).unwrap();
+        assert_eq!(fresh, Some((Lsn(200), Lsn(300))));
+
+        std::fs::remove_dir_all(&conf.workdir).ok();
+    }
+
+    #[test]
+    fn find_latest_delta_layer_ignores_snapshot_and_relsizes_files() {
+        let timelineid = ZTimelineId::generate();
+        let mut conf = PageServerConf::default();
+        conf.workdir = std::env::temp_dir().join(format!("pageserver-layer-index-test-{}", timelineid));
+        let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+
+        let timeline_dir = conf.timeline_path(timelineid);
+        std::fs::create_dir_all(&timeline_dir).unwrap();
+
+        let tag = RelTag {
+            spcnode: 1,
+            dbnode: 2,
+            relnode: 3,
+            forknum: 0,
+        };
+        let base = format!("{}_{}_{}_{}", tag.spcnode, tag.dbnode, tag.relnode, tag.forknum);
+        // A snapshot layer, its relsizes companion, and a delta layer with
+        // its own relsizes companion, all for the same RelTag and LSN range.
+        std::fs::write(
+            timeline_dir.join(format!("{}_{:016X}_{:016X}", base, 100, 200)),
+            b"",
+        )
+        .unwrap();
+        std::fs::write(
+            timeline_dir.join(format!("{}_{:016X}_{:016X}_relsizes", base, 100, 200)),
+            b"",
+        )
+        .unwrap();
+        std::fs::write(
+            timeline_dir.join(format!("{}_{:016X}_{:016X}_delta", base, 200, 300)),
+            b"",
+        )
+        .unwrap();
+        std::fs::write(
+            timeline_dir.join(format!("{}_{:016X}_{:016X}_delta_relsizes", base, 200, 300)),
+            b"",
+        )
+        .unwrap();
+
+        let snapshot = find_latest_snapshot_file(conf, timelineid, tag, Lsn(250)).unwrap();
+        assert_eq!(snapshot, Some((Lsn(100), Lsn(200))));
+
+        let delta = find_latest_delta_layer(conf, timelineid, tag, Lsn(250)).unwrap();
+        assert_eq!(delta, Some((Lsn(200), Lsn(300))));
+
+        std::fs::remove_dir_all(&conf.workdir).ok();
+    }
+}
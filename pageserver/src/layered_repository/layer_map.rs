@@ -0,0 +1,124 @@
+//!
+//! Glue between a timeline's delta layers and the snapshot layer that
+//! terminates their chain.
+//!
+//! `DeltaLayer::get_page_reconstruct_data` and `SnapshotLayer`'s own
+//! (private) counterpart each only look at their own layer's local history;
+//! neither one, on its own, is guaranteed to have everything needed to
+//! reconstruct a page. This module is the caller the doc comments on both
+//! of those types refer to: it finds the newest layer covering a given
+//! `(tag, lsn)` via `layer_index`, and if that's a delta layer that runs out
+//! of local history, walks down through progressively older delta layers
+//! until it reaches the terminating snapshot layer, concatenating the WAL
+//! records collected along the way before performing a single WAL-redo call.
+//!
+
+use crate::layered_repository::delta_layer::{DeltaLayer, PageReconstructResult};
+use crate::layered_repository::layer_index;
+use crate::layered_repository::snapshot_layer::SnapshotLayer;
+use crate::layered_repository::storage_layer::Layer;
+use crate::repository::{RelTag, WALRecord};
+use crate::walredo::WalRedoManager;
+use crate::PageServerConf;
+use crate::ZTimelineId;
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use zenith_utils::lsn::Lsn;
+
+/// Reconstruct the given page at `lsn`, walking the layer chain: the newest
+/// layer covering `lsn` and, for as long as that is a delta layer reporting
+/// `NeedsOlderLayer`, the next older layer, until a snapshot layer
+/// terminates the chain.
+///
+/// This is the entry point a timeline should use to read a page, in place
+/// of calling `SnapshotLayer::find_latest_snapshot_file` directly: it's the
+/// only caller that knows how to pick a `DeltaLayer` over a `SnapshotLayer`
+/// and to keep going when a delta layer alone isn't enough.
+pub fn get_page_at_lsn(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tag: RelTag,
+    blknum: u32,
+    lsn: Lsn,
+    walredo_mgr: &dyn WalRedoManager,
+) -> Result<Bytes> {
+    let mut collected: Vec<WALRecord> = Vec::new();
+    let mut continue_lsn = lsn;
+
+    loop {
+        let delta_range = layer_index::find_latest_delta_layer(conf, timelineid, tag, continue_lsn)?;
+        let snapshot_range =
+            layer_index::find_latest_snapshot_file(conf, timelineid, tag, continue_lsn)?;
+        // A snapshot layer starting at or after the newest delta layer is
+        // the newer of the two, and holds everything from its start on.
+        let newest_delta = match (delta_range, snapshot_range) {
+            (Some((delta_start, _)), Some((snapshot_start, _))) if snapshot_start >= delta_start => None,
+            (delta_range, _) => delta_range,
+        };
+        if let Some((start_lsn, end_lsn)) = newest_delta {
+            let delta = DeltaLayer::load(conf, timelineid, tag, start_lsn, end_lsn)?;
+            match delta.get_page_reconstruct_data(blknum, continue_lsn)? {
+                PageReconstructResult::Complete { page_img, records } => {
+                    let mut records = records;
+                    records.extend(std::mem::take(&mut collected));
+                    return redo_if_needed(walredo_mgr, tag, blknum, lsn, page_img, records);
+                }
+                PageReconstructResult::NeedsOlderLayer {
+                    continue_lsn: next_lsn,
+                    records,
+                } => {
+                    // `records` is this layer's own history, oldest first;
+                    // `collected` holds everything from newer layers walked
+                    // so far, which belongs after it.
+                    let mut records = records;
+                    records.extend(std::mem::take(&mut collected));
+                    collected = records;
+                    continue_lsn = next_lsn;
+                    continue;
+                }
+            }
+        }
+
+        // No delta layer newer than the latest snapshot layer covers
+        // `continue_lsn`: the snapshot layer terminates the chain.
+        let snapshot = SnapshotLayer::load(conf, timelineid, tag, continue_lsn)?.ok_or_else(|| {
+            anyhow!(
+                "no snapshot layer found for {} at {}/{} to terminate the reconstruction chain",
+                tag,
+                timelineid,
+                continue_lsn
+            )
+        })?;
+        let img = snapshot.get_page_at_lsn(walredo_mgr, blknum, continue_lsn)?;
+        return Ok(if collected.is_empty() {
+            img
+        } else {
+            walredo_mgr.request_redo(tag, blknum, lsn, Some(img), collected)?
+        });
+    }
+}
+
+/// Apply `records` on top of `page_img` (if any) via `walredo_mgr`, unless
+/// there's nothing to redo.
+fn redo_if_needed(
+    walredo_mgr: &dyn WalRedoManager,
+    tag: RelTag,
+    blknum: u32,
+    lsn: Lsn,
+    page_img: Option<Bytes>,
+    records: Vec<WALRecord>,
+) -> Result<Bytes> {
+    if records.is_empty() {
+        match page_img {
+            Some(img) => Ok(img),
+            None => bail!(
+                "no page image or WAL record for requested page {} blk {} at {}",
+                tag,
+                blknum,
+                lsn
+            ),
+        }
+    } else {
+        walredo_mgr.request_redo(tag, blknum, lsn, page_img, records)
+    }
+}
@@ -0,0 +1,283 @@
+//!
+//! `LayerStorage` abstracts over where layer files (snapshot and delta
+//! layers, and their `_relsizes` companions) actually live, so that
+//! `SnapshotLayer` and `DeltaLayer` don't have to hardcode local filesystem
+//! access. Two implementations exist: `LocalFsStorage`, the default, and
+//! `S3LayerStorage`, for faulting cold, historical layers out to cheap
+//! object storage (the way libsql offloads sealed WAL segments). `storage_for`
+//! picks between them based on `PageServerConf::remote_storage`.
+//!
+//! All paths passed to a `LayerStorage` are relative to one timeline's
+//! directory; it's up to the implementation to decide what that maps to
+//! (a subdirectory on disk, a key prefix in a bucket, ...).
+//!
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::path::{Path, PathBuf};
+
+/// A place layer files can be stored to and fetched back from.
+pub trait LayerStorage: Send + Sync {
+    /// Write `bytes` as the full contents of `path`, creating or
+    /// overwriting it.
+    fn put(&self, path: &Path, bytes: &[u8]) -> Result<()>;
+
+    /// Read back the full contents of `path`.
+    fn get(&self, path: &Path) -> Result<Bytes>;
+
+    /// List the paths currently stored under `prefix`. `LocalFsStorage`
+    /// lists all of them; see `S3LayerStorage`'s `list` for its limit.
+    fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>>;
+
+    /// Remove `path`. Not an error if it doesn't exist.
+    fn delete(&self, path: &Path) -> Result<()>;
+
+    /// Replace `to` with the current contents of `from`, and remove `from`.
+    /// Used to publish a freshly written file (e.g. written to a `.tmp`
+    /// path) without ever exposing a partially-written `to`.
+    /// `LocalFsStorage` does this with one atomic filesystem rename;
+    /// `S3LayerStorage` copies then deletes, so for a moment both exist.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Best-effort fast path for callers that want to seek around inside a
+    /// large file instead of reading all of it up front. Returns `Some` if
+    /// `path` is backed by a real local file that can be memory-mapped, and
+    /// `None` for backends with no such thing (e.g. a remote object-store
+    /// backend), in which case the caller should fall back to `get`.
+    fn mmap(&self, _path: &Path) -> Result<Option<memmap2::Mmap>> {
+        Ok(None)
+    }
+}
+
+/// Stores layer files directly on the local filesystem, under `base_dir`
+/// (normally a timeline's directory). This is the storage backend that was
+/// implicitly used everywhere before `LayerStorage` existed.
+pub struct LocalFsStorage {
+    base_dir: PathBuf,
+}
+
+impl LocalFsStorage {
+    pub fn new(base_dir: PathBuf) -> Self {
+        LocalFsStorage { base_dir }
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(path)
+    }
+}
+
+impl LayerStorage for LocalFsStorage {
+    fn put(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        std::fs::write(self.resolve(path), bytes)?;
+        Ok(())
+    }
+
+    fn get(&self, path: &Path) -> Result<Bytes> {
+        Ok(Bytes::from(std::fs::read(self.resolve(path))?))
+    }
+
+    fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>> {
+        let mut result = Vec::new();
+        for direntry in std::fs::read_dir(self.resolve(prefix))? {
+            let direntry = direntry?;
+            result.push(PathBuf::from(direntry.file_name()));
+        }
+        Ok(result)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        match std::fs::remove_file(self.resolve(path)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(self.resolve(from), self.resolve(to))?;
+        Ok(())
+    }
+
+    fn mmap(&self, path: &Path) -> Result<Option<memmap2::Mmap>> {
+        let file = std::fs::File::open(self.resolve(path))?;
+        // Safety: layer files are never modified in place after they're
+        // written (writers either `put` a new file once, or go through a
+        // `.tmp` path + `rename`), so the mapping can't observe a
+        // concurrent write.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Some(mmap))
+    }
+}
+
+/// Stores layer files as objects in an S3-compatible bucket, keyed by the
+/// timeline's id plus the path within it. Used once a timeline's
+/// `PageServerConf::remote_storage` names a bucket to fault cold layers out
+/// to; see `storage_for`, which is the only place that constructs one.
+///
+/// `LayerStorage`'s methods are synchronous, but the underlying `rusoto_s3`
+/// client is async, so each method blocks on `runtime` to bridge the two.
+/// This means `storage_for` (and hence every caller in this module) must
+/// run on a thread that's inside a tokio runtime whenever remote storage is
+/// configured -- true for every pageserver request-handling thread.
+pub struct S3LayerStorage {
+    bucket: rusoto_s3::S3Client,
+    bucket_name: String,
+    /// Every key this storage reads or writes is prefixed with this, so a
+    /// bucket can be shared across timelines (and tenants) without
+    /// collisions: `<prefix_in_bucket>/<timelineid>/<path>`.
+    key_prefix: String,
+    runtime: tokio::runtime::Handle,
+}
+
+impl S3LayerStorage {
+    fn new(
+        conf: &crate::config::RemoteStorageConfig,
+        timelineid: crate::ZTimelineId,
+        runtime: tokio::runtime::Handle,
+    ) -> Self {
+        let region = conf
+            .bucket_region
+            .parse::<rusoto_core::Region>()
+            .unwrap_or_else(|_| rusoto_core::Region::Custom {
+                name: conf.bucket_region.clone(),
+                endpoint: conf.bucket_region.clone(),
+            });
+
+        let prefix = conf.prefix_in_bucket.as_deref().unwrap_or("");
+        let key_prefix = format!("{}/{}", prefix.trim_end_matches('/'), timelineid)
+            .trim_start_matches('/')
+            .to_string();
+
+        S3LayerStorage {
+            bucket: rusoto_s3::S3Client::new(region),
+            bucket_name: conf.bucket_name.clone(),
+            key_prefix,
+            runtime,
+        }
+    }
+
+    fn key_for(&self, path: &Path) -> String {
+        format!("{}/{}", self.key_prefix, path.display())
+    }
+}
+
+impl LayerStorage for S3LayerStorage {
+    fn put(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        use rusoto_s3::S3;
+
+        let request = rusoto_s3::PutObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.key_for(path),
+            body: Some(bytes.to_vec().into()),
+            ..Default::default()
+        };
+        self.runtime
+            .block_on(self.bucket.put_object(request))
+            .context("S3 put_object failed")?;
+        Ok(())
+    }
+
+    fn get(&self, path: &Path) -> Result<Bytes> {
+        use futures::TryStreamExt;
+        use rusoto_s3::S3;
+
+        let request = rusoto_s3::GetObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.key_for(path),
+            ..Default::default()
+        };
+        let output = self
+            .runtime
+            .block_on(self.bucket.get_object(request))
+            .context("S3 get_object failed")?;
+        let body = output
+            .body
+            .ok_or_else(|| anyhow::anyhow!("S3 get_object for {:?} returned no body", path))?;
+        let chunks = self
+            .runtime
+            .block_on(body.map_ok(|chunk| chunk.to_vec()).try_concat())
+            .context("failed reading S3 object body")?;
+        Ok(Bytes::from(chunks))
+    }
+
+    /// Returns the keys from a single `ListObjectsV2` response, which S3
+    /// caps at 1,000; further pages of keys are not fetched.
+    fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>> {
+        use rusoto_s3::S3;
+
+        let full_prefix = self.key_for(prefix);
+        let request = rusoto_s3::ListObjectsV2Request {
+            bucket: self.bucket_name.clone(),
+            prefix: Some(full_prefix.clone()),
+            ..Default::default()
+        };
+        let output = self
+            .runtime
+            .block_on(self.bucket.list_objects_v2(request))
+            .context("S3 list_objects_v2 failed")?;
+
+        Ok(output
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter_map(|key| {
+                key.strip_prefix(&full_prefix)
+                    .map(|rest| PathBuf::from(rest.trim_start_matches('/')))
+            })
+            .collect())
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        use rusoto_s3::S3;
+
+        let request = rusoto_s3::DeleteObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.key_for(path),
+            ..Default::default()
+        };
+        self.runtime
+            .block_on(self.bucket.delete_object(request))
+            .context("S3 delete_object failed")?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        // S3 has no atomic rename; copy then delete is the best available
+        // approximation, and is what every S3-backed layer storage (e.g.
+        // libsql's) does for this case. This briefly exposes both keys
+        // rather than neither, which is the safer failure mode for a
+        // layer file: a reader might see the old name a moment longer,
+        // but never a missing one.
+        use rusoto_s3::S3;
+
+        let copy_request = rusoto_s3::CopyObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.key_for(to),
+            copy_source: format!("{}/{}", self.bucket_name, self.key_for(from)),
+            ..Default::default()
+        };
+        self.runtime
+            .block_on(self.bucket.copy_object(copy_request))
+            .context("S3 copy_object failed during rename")?;
+
+        self.delete(from)
+    }
+}
+
+/// The `LayerStorage` a timeline's layers should currently be read from and
+/// written to: `S3LayerStorage` if `conf.remote_storage` names a bucket,
+/// otherwise `LocalFsStorage` rooted at the timeline's local directory.
+pub fn storage_for(
+    conf: &'static crate::PageServerConf,
+    timelineid: crate::ZTimelineId,
+) -> Box<dyn LayerStorage> {
+    match &conf.remote_storage {
+        Some(remote) => Box::new(S3LayerStorage::new(
+            remote,
+            timelineid,
+            tokio::runtime::Handle::current(),
+        )),
+        None => Box::new(LocalFsStorage::new(conf.timeline_path(timelineid))),
+    }
+}
@@ -9,13 +9,19 @@
 //! page version in the LSN range, without consulting any other snapshot files. When
 //! a new snapshot file is created for writing, the full contents of relation are
 //! materialized as it is at the beginning of the LSN range. That can be very expensive,
-//! we should find a way to store differential files. But this keeps the read-side
-//! of things simple. You can find the correct snapshot file based on RelTag and
-//! timeline+LSN, and once you've located it, you have all the data you need to in that
-//! file.
+//! so a SnapshotLayer is also used as the chain terminator for `DeltaLayer`
+//! (see `delta_layer.rs`), which stores only the pages that changed within
+//! its own LSN range. A SnapshotLayer still keeps the read-side of things
+//! simple on its own: you can find the correct snapshot file based on
+//! RelTag and timeline+LSN, and once you've located it, you have all the
+//! data you need in that file.
 //!
-//! When a snapshot file needs to be accessed, we slurp the whole file into memory, into
-//! a SnapshotLayer struct.
+//! When a snapshot file needs to be accessed, we used to slurp the whole file into
+//! memory, into a SnapshotLayer struct. Now, if the file is stored locally and wasn't
+//! written with compression, we instead memory-map it and read only a small footer
+//! index (see `layer_io::serialize_indexed`), decoding individual page versions lazily
+//! as they're actually requested. That makes opening a layer near-instant and keeps
+//! memory use proportional to the working set rather than the layer's total size.
 //!
 //! On disk, a snapshot file is actually two files: one containing all the page versions,
 //! and another containing the relation size information. That's just for the convenience
@@ -33,6 +39,11 @@
 //!    1663_13990_2609_0_000000000169C348_000000000169C349_relsizes
 //!
 
+use crate::layered_repository::delta_layer::PageReconstructResult;
+use crate::layered_repository::layer_index;
+use crate::layered_repository::layer_io;
+use crate::layered_repository::layer_storage;
+use crate::layered_repository::layer_storage::LayerStorage;
 use crate::layered_repository::storage_layer::Layer;
 use crate::layered_repository::storage_layer::PageVersion;
 use crate::repository::{RelTag, WALRecord};
@@ -43,18 +54,64 @@ use anyhow::{bail, Result};
 use bytes::Bytes;
 use log::*;
 use std::collections::{BTreeMap, HashSet};
-use std::fs;
-use std::fs::File;
-use std::io::Write;
 use std::ops::Bound::Included;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
-use zenith_utils::bin_ser::BeSer;
 use zenith_utils::lsn::Lsn;
 
 static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; 8192]);
 
+/// The bytes backing a `PageVersions::Lazy` index: either a memory-mapped
+/// local file, or a plain in-memory buffer fetched from a storage backend
+/// (e.g. S3) that has no local file to map.
+enum ByteSource {
+    Mmap(memmap2::Mmap),
+    Bytes(Bytes),
+}
+
+impl std::ops::Deref for ByteSource {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ByteSource::Mmap(mmap) => mmap,
+            ByteSource::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// The page versions of a `SnapshotLayer`, either fully in memory or only
+/// indexed, with individual entries decoded on demand. A freshly created
+/// layer (`create`) is always `Eager`; one loaded back from disk (`load`)
+/// is `Lazy` whenever its storage and on-disk format allow it.
+enum PageVersions {
+    Eager(BTreeMap<(u32, Lsn), PageVersion>),
+    Lazy {
+        source: ByteSource,
+        index: BTreeMap<(u32, Lsn), layer_io::EntryLocation>,
+    },
+}
+
+impl PageVersions {
+    /// Ensure every page version is actually in memory, decoding any that
+    /// are still only indexed. A no-op if already `Eager`. Needed by
+    /// operations like `compact` that have to touch every entry anyway.
+    fn materialize(&mut self) -> Result<&mut BTreeMap<(u32, Lsn), PageVersion>> {
+        if let PageVersions::Lazy { source, index } = self {
+            let mut map = BTreeMap::new();
+            for (key, loc) in index.iter() {
+                map.insert(*key, layer_io::read_indexed_entry(source, *loc)?);
+            }
+            *self = PageVersions::Eager(map);
+        }
+        match self {
+            PageVersions::Eager(map) => Ok(map),
+            PageVersions::Lazy { .. } => unreachable!(),
+        }
+    }
+}
+
 ///
 /// SnapshotLayer is the in-memory data structure associated with an on-disk snapshot file.
 /// It is also used to accumulate new changes at the tip of a branch; end_lsn is u64::MAX
@@ -75,7 +132,7 @@ pub struct SnapshotLayer {
     /// All versions of all pages in the file are are kept here.
     /// Indexed by block number and LSN.
     ///
-    page_versions: Mutex<BTreeMap<(u32, Lsn), PageVersion>>,
+    page_versions: Mutex<PageVersions>,
 
     ///
     /// `relsizes` tracks the size of the relation at different points in time.
@@ -111,57 +168,15 @@ impl Layer for SnapshotLayer {
         blknum: u32,
         lsn: Lsn,
     ) -> Result<Bytes> {
-        // Scan the BTreeMap backwards, starting from the given entry.
-        let mut records: Vec<WALRecord> = Vec::new();
-        let mut page_img: Option<Bytes> = None;
-        let mut need_base_image_lsn: Option<Lsn> = Some(lsn);
-        {
-            let page_versions = self.page_versions.lock().unwrap();
-            let minkey = (blknum, Lsn(0));
-            let maxkey = (blknum, lsn);
-            let mut iter = page_versions.range((Included(&minkey), Included(&maxkey)));
-            while let Some(((_blknum, entry_lsn), entry)) = iter.next_back() {
-                if let Some(img) = &entry.page_image {
-                    page_img = Some(img.clone());
-                    need_base_image_lsn = None;
-                    break;
-                } else if let Some(rec) = &entry.record {
-                    records.push(rec.clone());
-                    if rec.will_init {
-                        // This WAL record initializes the page, so no need to go further back
-                        need_base_image_lsn = None;
-                        break;
-                    } else {
-                        need_base_image_lsn = Some(*entry_lsn);
-                    }
-                } else {
-                    // No base image, and no WAL record. Huh?
-                    bail!("no page image or WAL record for requested page");
-                }
-            }
-
-            // release lock on 'page_versions'
-        }
-        records.reverse();
-
-        // If we needed a base image to apply the WAL records against, we should have found it in memory.
-        if let Some(lsn) = need_base_image_lsn {
-            if records.is_empty() {
-                // no records, and no base image. This can happen if PostgreSQL extends a relation
-                // but never writes the page.
-                //
-                // Would be nice to detect that situation better.
-                warn!("Page {:?}/{} at {} not found", self.tag, blknum, lsn);
-                return Ok(ZERO_PAGE.clone());
+        let (page_img, records) = match self.get_page_reconstruct_data(blknum, lsn)? {
+            PageReconstructResult::Complete { page_img, records } => (page_img, records),
+            PageReconstructResult::NeedsOlderLayer { .. } => {
+                // A SnapshotLayer always holds a full base image for every
+                // block it covers, so it's always the chain terminator and
+                // never needs to defer to an older layer.
+                unreachable!("a SnapshotLayer's reconstruction is always Complete")
             }
-            bail!(
-                "No base image found for page {} blk {} at {}/{}",
-                self.tag,
-                blknum,
-                self.timelineid,
-                lsn
-            );
-        }
+        };
 
         // If we have a page image, and no WAL, we're all set
         if records.is_empty() {
@@ -267,23 +282,107 @@ impl Layer for SnapshotLayer {
 }
 
 impl SnapshotLayer {
+    /// Look up given page within this layer's own history. Unlike
+    /// `DeltaLayer::get_page_reconstruct_data`, this always holds a full
+    /// base image for any block it covers, so it always returns `Complete`
+    /// and never `NeedsOlderLayer`: this is the chainable counterpart of
+    /// `Layer::get_page_at_lsn` that terminates the layer-map's
+    /// reconstruction chain (see `layer_map::get_page_at_lsn`).
+    fn get_page_reconstruct_data(&self, blknum: u32, lsn: Lsn) -> Result<PageReconstructResult> {
+        // Scan the BTreeMap backwards, starting from the given entry.
+        let mut records: Vec<WALRecord> = Vec::new();
+        let mut page_img: Option<Bytes> = None;
+        let mut need_base_image_lsn: Option<Lsn> = Some(lsn);
+        {
+            let page_versions = self.page_versions.lock().unwrap();
+            let minkey = (blknum, Lsn(0));
+            let maxkey = (blknum, lsn);
+            match &*page_versions {
+                PageVersions::Eager(map) => {
+                    let mut iter = map.range((Included(&minkey), Included(&maxkey)));
+                    while let Some(((_blknum, entry_lsn), entry)) = iter.next_back() {
+                        if let Some(img) = &entry.page_image {
+                            page_img = Some(img.clone());
+                            need_base_image_lsn = None;
+                            break;
+                        } else if let Some(rec) = &entry.record {
+                            records.push(rec.clone());
+                            if rec.will_init {
+                                // This WAL record initializes the page, so no need to go further back
+                                need_base_image_lsn = None;
+                                break;
+                            } else {
+                                need_base_image_lsn = Some(*entry_lsn);
+                            }
+                        } else {
+                            // No base image, and no WAL record. Huh?
+                            bail!("no page image or WAL record for requested page");
+                        }
+                    }
+                }
+                PageVersions::Lazy { source, index } => {
+                    let mut iter = index.range((Included(&minkey), Included(&maxkey)));
+                    while let Some(((_blknum, entry_lsn), loc)) = iter.next_back() {
+                        let entry: PageVersion = layer_io::read_indexed_entry(source, *loc)?;
+                        if let Some(img) = entry.page_image {
+                            page_img = Some(img);
+                            need_base_image_lsn = None;
+                            break;
+                        } else if let Some(rec) = entry.record {
+                            let will_init = rec.will_init;
+                            records.push(rec);
+                            if will_init {
+                                // This WAL record initializes the page, so no need to go further back
+                                need_base_image_lsn = None;
+                                break;
+                            } else {
+                                need_base_image_lsn = Some(*entry_lsn);
+                            }
+                        } else {
+                            // No base image, and no WAL record. Huh?
+                            bail!("no page image or WAL record for requested page");
+                        }
+                    }
+                }
+            }
+
+            // release lock on 'page_versions'
+        }
+        records.reverse();
+
+        // If we needed a base image to apply the WAL records against, we should have found it in memory.
+        if let Some(lsn) = need_base_image_lsn {
+            if records.is_empty() {
+                // no records, and no base image. This can happen if PostgreSQL extends a relation
+                // but never writes the page.
+                //
+                // Would be nice to detect that situation better.
+                warn!("Page {:?}/{} at {} not found", self.tag, blknum, lsn);
+                return Ok(PageReconstructResult::Complete {
+                    page_img: Some(ZERO_PAGE.clone()),
+                    records: Vec::new(),
+                });
+            }
+            bail!(
+                "No base image found for page {} blk {} at {}/{}",
+                self.tag,
+                blknum,
+                self.timelineid,
+                lsn
+            );
+        }
+
+        Ok(PageReconstructResult::Complete { page_img, records })
+    }
+
+    /// Path of this layer's files, relative to its timeline directory. This
+    /// is the key `LayerStorage` operations are performed against, not
+    /// necessarily an absolute filesystem path.
     fn path(&self) -> PathBuf {
-        Self::path_for(
-            self.conf,
-            self.timelineid,
-            self.tag,
-            self.start_lsn,
-            self.end_lsn,
-        )
+        Self::fname_for(self.tag, self.start_lsn, self.end_lsn)
     }
 
-    fn path_for(
-        conf: &'static PageServerConf,
-        timelineid: ZTimelineId,
-        tag: RelTag,
-        start_lsn: Lsn,
-        end_lsn: Lsn,
-    ) -> PathBuf {
+    fn fname_for(tag: RelTag, start_lsn: Lsn, end_lsn: Lsn) -> PathBuf {
         let fname = format!(
             "{}_{}_{}_{}_{:016X}_{:016X}",
             tag.spcnode,
@@ -294,7 +393,7 @@ impl SnapshotLayer {
             u64::from(end_lsn)
         );
 
-        conf.timeline_path(timelineid).join(&fname)
+        PathBuf::from(fname)
     }
 
     fn relsizes_path(path: &Path) -> PathBuf {
@@ -325,33 +424,54 @@ impl SnapshotLayer {
             tag: tag,
             start_lsn: start_lsn,
             end_lsn,
-            page_versions: Mutex::new(page_versions),
+            page_versions: Mutex::new(PageVersions::Eager(page_versions)),
             relsizes: Mutex::new(relsizes),
         };
 
         snapfile.save()?;
+        layer_index::advance_generation(timelineid);
         Ok(snapfile)
     }
 
+    /// Serialize a page-versions map ready to write to disk: the
+    /// footer-indexed format (see `layer_io::serialize_indexed`), so a
+    /// later `load` can mmap it and decode entries lazily, or the plain
+    /// whole-buffer format when compression is requested, since a
+    /// compressed buffer can't be sought into entry by entry.
+    fn serialize_page_versions(
+        page_versions: &BTreeMap<(u32, Lsn), PageVersion>,
+        compress: bool,
+        compress_level: i32,
+    ) -> Result<Vec<u8>> {
+        if compress {
+            layer_io::serialize_layer_buf(page_versions, true, compress_level)
+        } else {
+            layer_io::serialize_indexed(page_versions)
+        }
+    }
+
     /// Write the in-memory btreemaps into files
     fn save(&self) -> Result<()> {
         let path = self.path();
+        let storage = layer_storage::storage_for(self.conf, self.timelineid);
 
-        let page_versions = self.page_versions.lock().unwrap();
+        let mut page_versions = self.page_versions.lock().unwrap();
         let relsizes = self.relsizes.lock().unwrap();
 
         // Note: This overwrites any existing file. There shouldn't be any.
         // FIXME: throw an error instead?
 
+        let compress = self.conf.compress_layers;
+        let compress_level = self.conf.compress_level.unwrap_or(0);
+
         // Write out page versions
-        let mut file = File::create(&path)?;
-        let buf = BTreeMap::ser(&page_versions)?;
-        file.write_all(&buf)?;
+        let buf =
+            Self::serialize_page_versions(page_versions.materialize()?, compress, compress_level)?;
+        storage.put(&path, &buf)?;
 
         // and relsizes to separate file
-        let mut file = File::create(Self::relsizes_path(&path))?;
-        let buf = BTreeMap::ser(&relsizes)?;
-        file.write_all(&buf)?;
+        let buf = layer_io::serialize_layer_buf(&*relsizes, compress, compress_level)?;
+        storage.put(&Self::relsizes_path(&path), &buf)?;
 
         debug!("saved {}", &path.display());
 
@@ -361,34 +481,15 @@ impl SnapshotLayer {
     ///
     /// Find the snapshot file with latest LSN that covers the given 'lsn', or is before it.
     ///
+    /// Backed by `layer_index`'s cached per-timeline scan, so this is a map
+    /// probe rather than a directory listing on every call.
     pub fn find_latest_snapshot_file(
         conf: &'static PageServerConf,
         timelineid: ZTimelineId,
         tag: RelTag,
         lsn: Lsn,
     ) -> Result<Option<(Lsn, Lsn)>> {
-        // Scan the timeline directory to get all rels in this timeline.
-        let path = conf.timeline_path(timelineid);
-        let mut result_start_lsn = Lsn(0);
-        let mut result_end_lsn = Lsn(0);
-        for direntry in fs::read_dir(path)? {
-            let direntry = direntry?;
-
-            let fname = direntry.file_name();
-            let fname = fname.to_str().unwrap();
-
-            if let Some((reltag, start_lsn, end_lsn)) = Self::fname_to_tag(fname) {
-                if reltag == tag && start_lsn <= lsn && start_lsn > result_start_lsn {
-                    result_start_lsn = start_lsn;
-                    result_end_lsn = end_lsn;
-                }
-            }
-        }
-        if result_start_lsn != Lsn(0) {
-            Ok(Some((result_start_lsn, result_end_lsn)))
-        } else {
-            Ok(None)
-        }
+        layer_index::find_latest_snapshot_file(conf, timelineid, tag, lsn)
     }
 
     ///
@@ -419,14 +520,34 @@ impl SnapshotLayer {
         start_lsn: Lsn,
         end_lsn: Lsn,
     ) -> Result<SnapshotLayer> {
-        let path = Self::path_for(conf, timelineid, tag, start_lsn, end_lsn);
-
-        let content = std::fs::read(&path)?;
-        let page_versions = BTreeMap::des(&content)?;
+        let path = Self::fname_for(tag, start_lsn, end_lsn);
+        let storage = layer_storage::storage_for(conf, timelineid);
+
+        let page_versions = if let Some(mmap) = storage.mmap(&path)? {
+            match layer_io::read_indexed_footer(&mmap)? {
+                Some(index) => PageVersions::Lazy {
+                    source: ByteSource::Mmap(mmap),
+                    index,
+                },
+                // Not the indexed format (compressed, or a legacy file):
+                // we already have the whole file mapped, so just
+                // deserialize it eagerly from there.
+                None => PageVersions::Eager(layer_io::read_layer_buf(&mmap)?),
+            }
+        } else {
+            let content = storage.get(&path)?;
+            match layer_io::read_indexed_footer(&content)? {
+                Some(index) => PageVersions::Lazy {
+                    source: ByteSource::Bytes(content),
+                    index,
+                },
+                None => PageVersions::Eager(layer_io::read_layer_buf(&content)?),
+            }
+        };
         debug!("loaded from {}", &path.display());
 
-        let content = std::fs::read(Self::relsizes_path(&path))?;
-        let relsizes = BTreeMap::des(&content)?;
+        let content = storage.get(&Self::relsizes_path(&path))?;
+        let relsizes = layer_io::read_layer_buf(&content)?;
         Ok(SnapshotLayer {
             conf,
             timelineid,
@@ -444,28 +565,10 @@ impl SnapshotLayer {
         spcnode: u32,
         dbnode: u32,
     ) -> Result<HashSet<RelTag>> {
-        let mut rels: HashSet<RelTag> = HashSet::new();
-
-        // Scan the timeline directory to get all rels in this timeline.
-        let path = conf.timeline_path(timelineid);
-        for direntry in fs::read_dir(path)? {
-            let direntry = direntry?;
-
-            let fname = direntry.file_name();
-            let fname = fname.to_str().unwrap();
-
-            if let Some((reltag, _start_lsn, _end_lsn)) = Self::fname_to_tag(fname) {
-                if (spcnode == 0 || reltag.spcnode == spcnode)
-                    && (dbnode == 0 || reltag.dbnode == dbnode)
-                {
-                    rels.insert(reltag);
-                }
-            }
-        }
-        Ok(rels)
+        layer_index::list_rels(conf, timelineid, spcnode, dbnode)
     }
 
-    fn fname_to_tag(fname: &str) -> Option<(RelTag, Lsn, Lsn)> {
+    pub(crate) fn fname_to_tag(fname: &str) -> Option<(RelTag, Lsn, Lsn)> {
         // Split the filename into parts
         //
         //    <spcnode>_<dbnode>_<relnode>_<forknum>_<start LSN>_<end LSN>
@@ -481,6 +584,201 @@ impl SnapshotLayer {
         let start_lsn = Lsn::from_hex(parts.next()?).ok()?;
         let end_lsn = Lsn::from_hex(parts.next()?).ok()?;
 
+        // Must parse as exactly this many parts, no more: otherwise this
+        // would also match the "_relsizes" companion file and any delta
+        // layer's "_delta"/"_delta_relsizes" files sharing the same
+        // directory, double-counting them as snapshot layers.
+        if parts.next().is_some() {
+            return None;
+        }
+
         Some((reltag, start_lsn, end_lsn))
     }
+
+    fn tmp_path(path: &Path) -> PathBuf {
+        let mut fname = path.file_name().unwrap().to_os_string();
+        fname.push(".tmp");
+        path.with_file_name(fname)
+    }
+
+    /// Write the in-memory btreemaps into files at a `.tmp` path, then
+    /// atomically rename them into place, so a reader can never observe a
+    /// partially-written layer file.
+    fn save_atomic(&self) -> Result<()> {
+        let path = self.path();
+        let storage = layer_storage::storage_for(self.conf, self.timelineid);
+
+        let mut page_versions = self.page_versions.lock().unwrap();
+        let relsizes = self.relsizes.lock().unwrap();
+        let compress = self.conf.compress_layers;
+        let compress_level = self.conf.compress_level.unwrap_or(0);
+
+        let relsizes_path = Self::relsizes_path(&path);
+        let tmp_path = Self::tmp_path(&path);
+        let tmp_relsizes_path = Self::tmp_path(&relsizes_path);
+
+        let buf =
+            Self::serialize_page_versions(page_versions.materialize()?, compress, compress_level)?;
+        storage.put(&tmp_path, &buf)?;
+        storage.rename(&tmp_path, &path)?;
+
+        let buf = layer_io::serialize_layer_buf(&*relsizes, compress, compress_level)?;
+        storage.put(&tmp_relsizes_path, &buf)?;
+        storage.rename(&tmp_relsizes_path, &relsizes_path)?;
+
+        debug!("saved {} atomically", &path.display());
+
+        Ok(())
+    }
+
+    ///
+    /// Merge a contiguous run of same-`RelTag` layers (`layers[i].end_lsn ==
+    /// layers[i + 1].start_lsn`) into one new layer spanning their union LSN
+    /// range. Below `gc_horizon`, only the newest page version per block is
+    /// kept; no branch can need an older version than that once they're all
+    /// past the horizon.
+    ///
+    /// The merged layer is written atomically (temp name + rename), and the
+    /// input layers' files are only deleted once that succeeds, so disk
+    /// usage and read fan-out stay bounded without ever exposing a
+    /// half-compacted state.
+    pub fn compact(
+        conf: &'static PageServerConf,
+        timelineid: ZTimelineId,
+        layers: &[SnapshotLayer],
+        gc_horizon: Lsn,
+    ) -> Result<SnapshotLayer> {
+        let first = layers.first().ok_or_else(|| anyhow::anyhow!("compact: no layers given"))?;
+        let tag = first.tag;
+        let start_lsn = first.start_lsn;
+        let end_lsn = layers.last().unwrap().end_lsn;
+
+        for pair in layers.windows(2) {
+            if pair[0].tag != tag || pair[1].tag != tag {
+                bail!("compact: all layers must be for the same RelTag");
+            }
+            if pair[0].end_lsn != pair[1].start_lsn {
+                bail!(
+                    "compact: layers must be contiguous: {} != {}",
+                    pair[0].end_lsn,
+                    pair[1].start_lsn
+                );
+            }
+        }
+
+        let mut merged_versions: BTreeMap<(u32, Lsn), PageVersion> = BTreeMap::new();
+        let mut merged_relsizes: BTreeMap<Lsn, u32> = BTreeMap::new();
+        for layer in layers {
+            let mut page_versions = layer.page_versions.lock().unwrap();
+            let mut relsizes = layer.relsizes.lock().unwrap();
+            merged_versions.extend(std::mem::take(page_versions.materialize()?));
+            merged_relsizes.extend(std::mem::take(&mut *relsizes));
+        }
+
+        let gced_versions = gc_below_horizon(merged_versions, gc_horizon);
+
+        let merged = SnapshotLayer {
+            conf,
+            timelineid,
+            tag,
+            start_lsn,
+            end_lsn,
+            page_versions: Mutex::new(PageVersions::Eager(gced_versions)),
+            relsizes: Mutex::new(merged_relsizes),
+        };
+
+        merged.save_atomic()?;
+
+        let storage = layer_storage::storage_for(conf, timelineid);
+        for layer in layers {
+            let path = layer.path();
+            storage.delete(&path)?;
+            storage.delete(&Self::relsizes_path(&path))?;
+        }
+
+        layer_index::advance_generation(timelineid);
+
+        Ok(merged)
+    }
+}
+
+/// Keep only the newest version of each block below `gc_horizon`, dropping
+/// every older one; versions at or above the horizon are kept as-is. This
+/// is the core merge/GC decision behind `SnapshotLayer::compact`, split out
+/// as a free function (generic over the version type) so it can be tested
+/// without needing a real `PageVersion` or any of `compact`'s I/O.
+fn gc_below_horizon<V>(versions: BTreeMap<(u32, Lsn), V>, gc_horizon: Lsn) -> BTreeMap<(u32, Lsn), V> {
+    let mut gced: BTreeMap<(u32, Lsn), V> = BTreeMap::new();
+    let mut pending_below_horizon: Option<((u32, Lsn), V)> = None;
+    for (key, version) in versions {
+        let (blknum, lsn) = key;
+        if let Some(((pending_blknum, _), _)) = &pending_below_horizon {
+            if *pending_blknum != blknum {
+                let (pending_key, pending_version) = pending_below_horizon.take().unwrap();
+                gced.insert(pending_key, pending_version);
+            }
+        }
+        if lsn < gc_horizon {
+            pending_below_horizon = Some((key, version));
+        } else {
+            gced.insert(key, version);
+        }
+    }
+    if let Some((pending_key, pending_version)) = pending_below_horizon.take() {
+        gced.insert(pending_key, pending_version);
+    }
+    gced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gc_below_horizon_keeps_newest_version_below_horizon_per_block() {
+        let mut versions = BTreeMap::new();
+        versions.insert((1, Lsn(10)), "a");
+        versions.insert((1, Lsn(20)), "b");
+        versions.insert((1, Lsn(30)), "c");
+
+        let gced = gc_below_horizon(versions, Lsn(40));
+
+        // All three are below the horizon, so only the newest (lsn 30)
+        // should survive.
+        assert_eq!(gced, BTreeMap::from([((1, Lsn(30)), "c")]));
+    }
+
+    #[test]
+    fn gc_below_horizon_keeps_everything_at_or_above_horizon() {
+        let mut versions = BTreeMap::new();
+        versions.insert((1, Lsn(10)), "a");
+        versions.insert((1, Lsn(50)), "b");
+        versions.insert((1, Lsn(60)), "c");
+
+        let gced = gc_below_horizon(versions, Lsn(40));
+
+        // lsn 10 is the only one below the horizon, so it's the one that
+        // gets collapsed down to (nothing else is below it); 50 and 60 are
+        // both at or above the horizon and survive untouched.
+        assert_eq!(
+            gced,
+            BTreeMap::from([((1, Lsn(10)), "a"), ((1, Lsn(50)), "b"), ((1, Lsn(60)), "c")])
+        );
+    }
+
+    #[test]
+    fn gc_below_horizon_handles_multiple_blocks_independently() {
+        let mut versions = BTreeMap::new();
+        versions.insert((1, Lsn(10)), "a1");
+        versions.insert((1, Lsn(20)), "a2");
+        versions.insert((2, Lsn(15)), "b1");
+        versions.insert((2, Lsn(25)), "b2");
+
+        let gced = gc_below_horizon(versions, Lsn(100));
+
+        assert_eq!(
+            gced,
+            BTreeMap::from([((1, Lsn(20)), "a2"), ((2, Lsn(25)), "b2")])
+        );
+    }
 }
\ No newline at end of file
@@ -12,7 +12,10 @@
 //! that are meant to be portable (particularly data structures sent)
 
 use anyhow::{anyhow, Result};
-use zerocopy::{AsBytes, FromBytes, LayoutVerified};
+use std::fmt;
+use std::io::{Read, Write};
+use zerocopy::byteorder::{BigEndian, LittleEndian, F64, I64, U32, U64};
+use zerocopy::{AsBytes, ByteOrder, FromBytes, LayoutVerified, TryFromBytes};
 
 pub const PG_CONTROL_FILE_SIZE: usize = 8192;
 pub type Oid = u32;
@@ -34,6 +37,44 @@ pub const DBState_DB_IN_CRASH_RECOVERY: DBState = 4;
 pub const DBState_DB_IN_ARCHIVE_RECOVERY: DBState = 5;
 pub const DBState_DB_IN_PRODUCTION: DBState = 6;
 
+/// Validated counterpart of the raw [`DBState`] integer.
+///
+/// `decode` reads `state` as a plain `u32`, so a corrupt control file with
+/// a value outside the known range silently yields nonsense instead of an
+/// error. `decode_checked` instead reads it as this type, which derives
+/// zerocopy's `TryFromBytes` and so rejects any bit pattern that isn't one
+/// of the defined variants.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromBytes)]
+pub enum DBStateChecked {
+    Startup = 0,
+    Shutdowned = 1,
+    ShutdownedInRecovery = 2,
+    Shutdowning = 3,
+    InCrashRecovery = 4,
+    InArchiveRecovery = 5,
+    InProduction = 6,
+}
+
+/// A C `bool`-as-`u8` field, validated on decode.
+///
+/// `decode` reads these fields as plain `u8`s, so a corrupt control file
+/// with a byte that is neither 0 nor 1 is silently accepted. `decode_checked`
+/// instead reads them as this type, which derives `TryFromBytes` and so
+/// rejects any byte other than 0 or 1.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromBytes)]
+pub enum CBool {
+    False = 0,
+    True = 1,
+}
+
+impl From<CBool> for bool {
+    fn from(b: CBool) -> bool {
+        matches!(b, CBool::True)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default, AsBytes, FromBytes)]
 pub struct FullTransactionId {
@@ -127,50 +168,205 @@ pub struct ControlFileData {
     pub __padding7: [u8; 4],
 }
 
+/// Reasons decoding a control file can fail. [`ControlFileData::decode`]
+/// returns every variant except `UnsupportedVersion`, which only
+/// [`VersionedControlFileData::decode`] returns.
+///
+/// Unlike a plain `anyhow::Error`, this lets callers programmatically
+/// distinguish a size/alignment failure from a CRC mismatch from an
+/// unsupported control file version, similar to how `std::io::Error`
+/// exposes a categorizable `ErrorKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlFileError {
+    /// The buffer was shorter than a `ControlFileData`.
+    TooShort { expected: usize, got: usize },
+    /// The buffer was not aligned for a `ControlFileData`.
+    Misaligned,
+    /// The CRC stored in the control file doesn't match the CRC computed
+    /// over its contents.
+    CrcMismatch { expected: u32, actual: u32 },
+    /// The control file's `pg_control_version` isn't one this crate knows
+    /// how to decode.
+    UnsupportedVersion { got: u32, supported: &'static [u32] },
+}
+
+impl fmt::Display for ControlFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlFileError::TooShort { expected, got } => write!(
+                f,
+                "control file buffer too short: expected at least {} bytes, got {}",
+                expected, got
+            ),
+            ControlFileError::Misaligned => {
+                write!(f, "control file buffer is not sufficiently aligned")
+            }
+            ControlFileError::CrcMismatch { expected, actual } => write!(
+                f,
+                "invalid CRC in control file: expected {:08X}, was {:08X}",
+                expected, actual
+            ),
+            ControlFileError::UnsupportedVersion { got, supported } => write!(
+                f,
+                "unsupported control file version {}: this crate can only decode {:?}",
+                got, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ControlFileError {}
+
+impl From<ControlFileError> for std::io::Error {
+    fn from(err: ControlFileError) -> std::io::Error {
+        let kind = match err {
+            ControlFileError::TooShort { .. } => std::io::ErrorKind::UnexpectedEof,
+            ControlFileError::Misaligned
+            | ControlFileError::CrcMismatch { .. }
+            | ControlFileError::UnsupportedVersion { .. } => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
 impl ControlFileData {
-    // FIXME: compute this in a better way, or remove it entirely?
-    const OFFSETOF_CRC: usize = 288;
+    /// Byte offset of the `crc` field within `ControlFileData`, i.e. the
+    /// length of the CRC-covered prefix of the struct.
+    ///
+    /// This used to be a hardcoded magic constant (288), which silently
+    /// broke if the struct's field order or padding ever changed. Deriving
+    /// it from the struct itself keeps it correct automatically.
+    fn offsetof_crc() -> usize {
+        let base = ControlFileData::default();
+        let base_addr = &base as *const ControlFileData as usize;
+        let crc_addr = &base.crc as *const u32 as usize;
+        crc_addr - base_addr
+    }
 
     /// Decode a `ControlFileData` struct from a byte array.
     ///
     /// This action is non-portable; it may fail to read data written by other
     /// CPU architectures.
     ///
-    pub fn decode(buf: &[u8]) -> Result<ControlFileData> {
-        // Verify correct buffer alignment and size.
+    pub fn decode(buf: &[u8]) -> Result<ControlFileData, ControlFileError> {
+        let expected_size = std::mem::size_of::<ControlFileData>();
+        if buf.len() < expected_size {
+            return Err(ControlFileError::TooShort {
+                expected: expected_size,
+                got: buf.len(),
+            });
+        }
+        if (buf.as_ptr() as usize) % std::mem::align_of::<ControlFileData>() != 0 {
+            return Err(ControlFileError::Misaligned);
+        }
+
+        // Size and alignment were just checked above, so this cannot fail.
         let (layout, _remaining) = LayoutVerified::<_, ControlFileData>::new_from_prefix(buf)
-            .ok_or(anyhow!("failed to get LayoutVerified ref"))?;
+            .expect("size and alignment were already checked");
 
         // Safely transmute into &ControlFileData, and then clone to get an owned copy.
         let controlfile = layout.into_ref().clone();
 
         // Compute expected CRC.
-        // Note the buffer length was already checked by LayoutVerified, so
+        // Note the buffer length was already checked above, so
         // accessing this offset should never panic.
-        let data_without_crc = &buf[0..Self::OFFSETOF_CRC];
+        let data_without_crc = &buf[0..Self::offsetof_crc()];
         let expectedcrc = crc32c::crc32c(&data_without_crc);
 
         if expectedcrc != controlfile.crc {
-            anyhow::bail!(
-                "invalid CRC in control file: expected {:08X}, was {:08X}",
-                expectedcrc,
-                controlfile.crc
-            );
+            return Err(ControlFileError::CrcMismatch {
+                expected: expectedcrc,
+                actual: controlfile.crc,
+            });
         }
 
         Ok(controlfile)
     }
 
+    /// Like [`decode`](Self::decode), but additionally validates every
+    /// field whose valid values are a strict subset of its underlying
+    /// integer type: `state`, and the several C-`bool`-as-`u8` fields
+    /// (`fullPageWrites`, `backupEndRequired`, `wal_log_hints`,
+    /// `track_commit_timestamp`, `float8ByVal`).
+    ///
+    /// `decode` accepts any bit pattern in these fields, so a corrupt
+    /// control file silently yields nonsense. This method instead returns
+    /// an error naming the first field found holding a byte pattern
+    /// outside its defined variants.
+    pub fn decode_checked(buf: &[u8]) -> Result<ControlFileData> {
+        let controlfile = Self::decode(buf)?;
+
+        Self::validate_enum_field("state", controlfile.state)?;
+        Self::validate_bool_field("checkPointCopy.fullPageWrites", controlfile.checkPointCopy.fullPageWrites)?;
+        Self::validate_bool_field("backupEndRequired", controlfile.backupEndRequired)?;
+        Self::validate_bool_field("wal_log_hints", controlfile.wal_log_hints)?;
+        Self::validate_bool_field(
+            "track_commit_timestamp",
+            controlfile.track_commit_timestamp,
+        )?;
+        Self::validate_bool_field("float8ByVal", controlfile.float8ByVal)?;
+
+        Ok(controlfile)
+    }
+
+    fn validate_enum_field(name: &str, value: DBState) -> Result<()> {
+        DBStateChecked::try_read_from(&value.to_ne_bytes()[..])
+            .ok_or_else(|| anyhow!("invalid value for field {}: {}", name, value))?;
+        Ok(())
+    }
+
+    fn validate_bool_field(name: &str, value: u8) -> Result<()> {
+        CBool::try_read_from(&[value][..])
+            .ok_or_else(|| anyhow!("invalid value for bool field {}: {}", name, value))?;
+        Ok(())
+    }
+
+    /// Read and decode a `ControlFileData` directly from `reader`.
+    ///
+    /// This reads exactly [`PG_CONTROL_FILE_SIZE`] bytes into an internally
+    /// aligned buffer before running the same validation as
+    /// [`decode`](Self::decode), so callers working against a `File` or
+    /// `BufReader` don't need to manage alignment themselves.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<ControlFileData> {
+        // `ControlFileData` requires 8-byte alignment; force the buffer to
+        // that alignment so `decode`'s `LayoutVerified` cast succeeds.
+        #[repr(align(8))]
+        struct AlignedBuf([u8; PG_CONTROL_FILE_SIZE]);
+
+        let mut buf = AlignedBuf([0u8; PG_CONTROL_FILE_SIZE]);
+        reader.read_exact(&mut buf.0)?;
+        Ok(Self::decode(&buf.0)?)
+    }
+
+    /// Encode `self` and write it to `writer` as a full control-file-sized
+    /// block, zero-padding the tail beyond the struct.
+    ///
+    /// This is the `Write` counterpart of [`read_from`](Self::read_from).
+    pub fn write_to<W: Write>(self, writer: &mut W) -> Result<()> {
+        let encoded = self.encode();
+
+        let mut block = [0u8; PG_CONTROL_FILE_SIZE];
+        block[..encoded.len()].copy_from_slice(&encoded);
+        writer.write_all(&block)?;
+        Ok(())
+    }
+
     /// Encode a `ControlFileData` struct into a byte array.
     ///
     /// This action is non-portable; it may fail to read data written by other
     /// CPU architectures.
     ///
     pub fn encode(mut self) -> Box<[u8]> {
+        // Instances built by transmuting from the implicitly-padded bindgen
+        // struct can carry residual/uninitialized bytes in the `__paddingN`
+        // fields. Zero them first, so the CRC and the serialized bytes are
+        // byte-identical to what PostgreSQL's memset-then-fill path writes.
+        self.zero_padding();
+
         let cf_bytes = self.as_bytes();
 
         // Recompute the CRC
-        let data_without_crc = &cf_bytes[0..Self::OFFSETOF_CRC];
+        let data_without_crc = &cf_bytes[0..Self::offsetof_crc()];
         let newcrc = crc32c::crc32c(&data_without_crc);
 
         // Drop the immutable reference so we can modify the struct
@@ -181,6 +377,481 @@ impl ControlFileData {
         let cf_bytes = self.as_bytes();
         cf_bytes.into()
     }
+
+    /// Zero every `__paddingN` field of `self` and of the nested
+    /// `checkPointCopy`, so they can't leak uninitialized bytes into the
+    /// CRC or the serialized output.
+    fn zero_padding(&mut self) {
+        self.__padding1 = [0; 4];
+        self.__padding2 = [0; 4];
+        self.__padding3 = [0; 3];
+        self.__padding4 = [0; 3];
+        self.__padding5 = [0; 3];
+        self.__padding6 = [0; 3];
+        self.__padding7 = [0; 4];
+        self.checkPointCopy.zero_padding();
+    }
+}
+
+impl CheckPoint {
+    /// Zero every `__paddingN` field of `self`.
+    fn zero_padding(&mut self) {
+        self.__padding1 = [0; 7];
+        self.__padding4 = [0; 4];
+        self.__padding5 = [0; 4];
+    }
+}
+
+/// The current, native-endian `ControlFileData`/`CheckPoint` layout defined
+/// above, tagged with the `pg_control_version` it corresponds to.
+///
+/// This exists so [`VersionedControlFileData`] has something to dispatch
+/// to alongside [`v15`]; see that module for why there are two.
+pub mod v16 {
+    pub use super::{CheckPoint, ControlFileData};
+
+    /// The `pg_control_version` this layout was ported from.
+    pub const PG_CONTROL_VERSION: u32 = 1300;
+}
+
+/// The `ControlFileData` layout from before `mock_authentication_nonce` was
+/// added (for SCRAM mock authentication), kept so a control file written by
+/// an older postgres can still be read.
+pub mod v15 {
+    use super::{
+        CBool, CheckPoint, ControlFileError, DBState, DBStateChecked, TimeLineID, XLogRecPtr,
+        pg_time_t,
+    };
+    use anyhow::{anyhow, Result};
+    use zerocopy::{AsBytes, FromBytes, LayoutVerified, TryFromBytes};
+
+    /// The `pg_control_version` this layout was ported from.
+    pub const PG_CONTROL_VERSION: u32 = 1201;
+
+    /// Same as [`super::ControlFileData`], minus the trailing
+    /// `mock_authentication_nonce` field that was added in a later release.
+    #[repr(C)]
+    #[derive(Debug, Clone, Default, AsBytes, FromBytes)]
+    pub struct ControlFileData {
+        pub system_identifier: u64,
+        pub pg_control_version: u32,
+        pub catalog_version_no: u32,
+        pub state: DBState,
+        /// Explicit padding to align the 64-bit field that follows.
+        pub __padding1: [u8; 4],
+        pub time: pg_time_t,
+        pub checkPoint: XLogRecPtr,
+        pub checkPointCopy: CheckPoint,
+        pub unloggedLSN: XLogRecPtr,
+        pub minRecoveryPoint: XLogRecPtr,
+        pub minRecoveryPointTLI: TimeLineID,
+        /// Explicit padding to align the 64-bit field that follows.
+        pub __padding2: [u8; 4],
+        pub backupStartPoint: XLogRecPtr,
+        pub backupEndPoint: XLogRecPtr,
+        /// Note this is `bool` in C; it is u8 to allow safe conversions.
+        pub backupEndRequired: u8,
+        /// Explicit padding to align the 32-bit field that follows.
+        pub __padding3: [u8; 3],
+        pub wal_level: u32,
+        /// Note this is `bool` in C; it is u8 to allow safe conversions.
+        pub wal_log_hints: u8,
+        /// Explicit padding to align the 32-bit field that follows.
+        pub __padding4: [u8; 3],
+        pub MaxConnections: u32,
+        pub max_worker_processes: u32,
+        pub max_wal_senders: u32,
+        pub max_prepared_xacts: u32,
+        pub max_locks_per_xact: u32,
+        /// Note this is `bool` in C; it is u8 to allow safe conversions.
+        pub track_commit_timestamp: u8,
+        /// Explicit padding to align the 32-bit field that follows.
+        pub __padding5: [u8; 3],
+        pub maxAlign: u32,
+        pub floatFormat: f64,
+        pub blcksz: u32,
+        pub relseg_size: u32,
+        pub xlog_blcksz: u32,
+        pub xlog_seg_size: u32,
+        pub nameDataLen: u32,
+        pub indexMaxKeys: u32,
+        pub toast_max_chunk_size: u32,
+        pub loblksize: u32,
+        // /// Note this is `bool` in C; it is u8 to allow safe conversions.
+        pub float8ByVal: u8,
+        /// Explicit padding to align the 32-bit field that follows.
+        pub __padding6: [u8; 3],
+        pub data_checksum_version: u32,
+        // No `mock_authentication_nonce` in this layout: it was introduced
+        // alongside SCRAM mock authentication, after `PG_CONTROL_VERSION`.
+        pub crc: u32,
+        /// Explicit padding to align the end of the struct, to satisfy `zerocopy`
+        pub __padding7: [u8; 4],
+    }
+
+    impl ControlFileData {
+        /// Byte offset of the `crc` field, i.e. the length of the
+        /// CRC-covered prefix of the struct. See
+        /// [`super::ControlFileData::offsetof_crc`] for why this is derived
+        /// rather than hardcoded.
+        fn offsetof_crc() -> usize {
+            let base = ControlFileData::default();
+            let base_addr = &base as *const ControlFileData as usize;
+            let crc_addr = &base.crc as *const u32 as usize;
+            crc_addr - base_addr
+        }
+
+        /// Decode a `v15::ControlFileData` from a byte array. See
+        /// [`super::ControlFileData::decode`] for the native-layout
+        /// counterpart this mirrors.
+        pub fn decode(buf: &[u8]) -> Result<ControlFileData, ControlFileError> {
+            let expected_size = std::mem::size_of::<ControlFileData>();
+            if buf.len() < expected_size {
+                return Err(ControlFileError::TooShort {
+                    expected: expected_size,
+                    got: buf.len(),
+                });
+            }
+            if (buf.as_ptr() as usize) % std::mem::align_of::<ControlFileData>() != 0 {
+                return Err(ControlFileError::Misaligned);
+            }
+
+            let (layout, _remaining) = LayoutVerified::<_, ControlFileData>::new_from_prefix(buf)
+                .expect("size and alignment were already checked");
+            let controlfile = layout.into_ref().clone();
+
+            let data_without_crc = &buf[0..Self::offsetof_crc()];
+            let expectedcrc = crc32c::crc32c(data_without_crc);
+            if expectedcrc != controlfile.crc {
+                return Err(ControlFileError::CrcMismatch {
+                    expected: expectedcrc,
+                    actual: controlfile.crc,
+                });
+            }
+
+            Ok(controlfile)
+        }
+
+        /// Like [`decode`](Self::decode), validating the same fields as
+        /// [`super::ControlFileData::decode_checked`].
+        pub fn decode_checked(buf: &[u8]) -> Result<ControlFileData> {
+            let controlfile = Self::decode(buf)?;
+
+            Self::validate_enum_field("state", controlfile.state)?;
+            Self::validate_bool_field(
+                "checkPointCopy.fullPageWrites",
+                controlfile.checkPointCopy.fullPageWrites,
+            )?;
+            Self::validate_bool_field("backupEndRequired", controlfile.backupEndRequired)?;
+            Self::validate_bool_field("wal_log_hints", controlfile.wal_log_hints)?;
+            Self::validate_bool_field("track_commit_timestamp", controlfile.track_commit_timestamp)?;
+            Self::validate_bool_field("float8ByVal", controlfile.float8ByVal)?;
+
+            Ok(controlfile)
+        }
+
+        fn validate_enum_field(name: &str, value: DBState) -> Result<()> {
+            DBStateChecked::try_read_from(&value.to_ne_bytes()[..])
+                .ok_or_else(|| anyhow!("invalid value for field {}: {}", name, value))?;
+            Ok(())
+        }
+
+        fn validate_bool_field(name: &str, value: u8) -> Result<()> {
+            CBool::try_read_from(&[value][..])
+                .ok_or_else(|| anyhow!("invalid value for bool field {}: {}", name, value))?;
+            Ok(())
+        }
+
+        /// Encode a `v15::ControlFileData` into a byte array. See
+        /// [`super::ControlFileData::encode`] for the native-layout
+        /// counterpart this mirrors.
+        pub fn encode(mut self) -> Box<[u8]> {
+            self.zero_padding();
+
+            let cf_bytes = self.as_bytes();
+            let newcrc = crc32c::crc32c(&cf_bytes[0..Self::offsetof_crc()]);
+            drop(cf_bytes);
+            self.crc = newcrc;
+
+            self.as_bytes().into()
+        }
+
+        /// Zero every `__paddingN` field of `self` and of the nested
+        /// `checkPointCopy`. See [`super::ControlFileData::zero_padding`].
+        fn zero_padding(&mut self) {
+            self.__padding1 = [0; 4];
+            self.__padding2 = [0; 4];
+            self.__padding3 = [0; 3];
+            self.__padding4 = [0; 3];
+            self.__padding5 = [0; 3];
+            self.__padding6 = [0; 3];
+            self.__padding7 = [0; 4];
+            self.checkPointCopy.zero_padding();
+        }
+    }
+}
+
+/// A `ControlFileData`, decoded as whichever per-`pg_control_version`
+/// layout its buffer declares itself to be.
+///
+/// PostgreSQL has changed the on-disk `ControlFileData` layout across major
+/// versions (for example, adding `mock_authentication_nonce`). A reader
+/// that only knows the newest layout can't open an older cluster's control
+/// file; this type peeks `pg_control_version` out of the buffer first and
+/// dispatches to the matching layout in [`v15`] or [`v16`].
+#[derive(Debug, Clone)]
+pub enum VersionedControlFileData {
+    V16(v16::ControlFileData),
+    V15(v15::ControlFileData),
+}
+
+/// Byte offset of `pg_control_version` within `ControlFileData`. Both
+/// [`v15::ControlFileData`] and [`v16::ControlFileData`]/[`ControlFileData`]
+/// agree on this offset: `pg_control_version` is the second field in every
+/// layout this crate knows about, and no layout has ever reordered the
+/// fields ahead of it.
+const PG_CONTROL_VERSION_OFFSET: usize = std::mem::size_of::<u64>();
+
+impl VersionedControlFileData {
+    /// Decode a control file buffer, dispatching on its `pg_control_version`
+    /// to the matching per-version layout.
+    pub fn decode(buf: &[u8]) -> Result<VersionedControlFileData, ControlFileError> {
+        let version = Self::peek_pg_control_version(buf)?;
+        match version {
+            v16::PG_CONTROL_VERSION => {
+                v16::ControlFileData::decode(buf).map(VersionedControlFileData::V16)
+            }
+            v15::PG_CONTROL_VERSION => {
+                v15::ControlFileData::decode(buf).map(VersionedControlFileData::V15)
+            }
+            got => Err(ControlFileError::UnsupportedVersion {
+                got,
+                supported: &[v16::PG_CONTROL_VERSION, v15::PG_CONTROL_VERSION],
+            }),
+        }
+    }
+
+    /// Read the raw `pg_control_version` field out of `buf` without
+    /// otherwise interpreting the buffer, so [`decode`](Self::decode) knows
+    /// which layout to parse the rest of it with.
+    fn peek_pg_control_version(buf: &[u8]) -> Result<u32, ControlFileError> {
+        let end = PG_CONTROL_VERSION_OFFSET + std::mem::size_of::<u32>();
+        if buf.len() < end {
+            return Err(ControlFileError::TooShort {
+                expected: end,
+                got: buf.len(),
+            });
+        }
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[PG_CONTROL_VERSION_OFFSET..end]);
+        Ok(u32::from_ne_bytes(bytes))
+    }
+}
+
+/// A byte-order-aware mirror of [`FullTransactionId`], for use by
+/// [`PortableControlFileData`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, AsBytes, FromBytes)]
+pub struct PortableFullTransactionId<O: ByteOrder> {
+    pub value: U64<O>,
+}
+
+/// A byte-order-aware mirror of [`CheckPoint`], for use by
+/// [`PortableControlFileData`]. See that type for more background.
+#[repr(C)]
+#[derive(Debug, Clone, Default, AsBytes, FromBytes)]
+pub struct PortableCheckPoint<O: ByteOrder> {
+    pub redo: U64<O>,
+    pub ThisTimeLineID: U32<O>,
+    pub PrevTimeLineID: U32<O>,
+    /// Note this is `bool` in C; it is u8 to allow safe conversions.
+    pub fullPageWrites: u8,
+    /// Explicit padding to align the 64-bit field that follows.
+    pub __padding1: [u8; 7],
+    pub nextXid: PortableFullTransactionId<O>,
+    pub nextOid: U32<O>,
+    pub nextMulti: U32<O>,
+    pub nextMultiOffset: U32<O>,
+    pub oldestXid: U32<O>,
+    pub oldestXidDB: U32<O>,
+    pub oldestMulti: U32<O>,
+    pub oldestMultiDB: U32<O>,
+    /// Explicit padding to align the 64-bit field that follows.
+    pub __padding4: [u8; 4],
+    pub time: I64<O>,
+    pub oldestCommitTsXid: U32<O>,
+    pub newestCommitTsXid: U32<O>,
+    pub oldestActiveXid: U32<O>,
+    /// Explicit padding to align the end of the struct, so this
+    /// struct can be included inside other structs.
+    pub __padding5: [u8; 4],
+}
+
+/// A byte-order-parameterized mirror of [`ControlFileData`].
+///
+/// [`ControlFileData`] assumes the control file was written by a process
+/// running with the host's native endianness, which is all PostgreSQL
+/// itself ever does. This type instead expresses every multi-byte field
+/// with zerocopy's endian-aware integer wrappers (`U32<O>`, `U64<O>`, ...)
+/// parameterized over a `ByteOrder` marker, so the in-memory
+/// representation keeps the on-disk byte order regardless of which
+/// architecture is reading it. This makes it possible to read a control
+/// file written on, say, aarch64 or s390x from an x86_64 host, and vice
+/// versa.
+///
+/// The CRC is computed over the raw bytes as they appear on disk, so it
+/// is unaffected by which `O` is chosen; only the field accessors
+/// (`.get()`/`.set()`) differ from [`ControlFileData`].
+#[repr(C)]
+#[derive(Debug, Clone, Default, AsBytes, FromBytes)]
+pub struct PortableControlFileData<O: ByteOrder> {
+    pub system_identifier: U64<O>,
+    pub pg_control_version: U32<O>,
+    pub catalog_version_no: U32<O>,
+    pub state: U32<O>,
+    /// Explicit padding to align the 64-bit field that follows.
+    pub __padding1: [u8; 4],
+    pub time: I64<O>,
+    pub checkPoint: U64<O>,
+    pub checkPointCopy: PortableCheckPoint<O>,
+    pub unloggedLSN: U64<O>,
+    pub minRecoveryPoint: U64<O>,
+    pub minRecoveryPointTLI: U32<O>,
+    /// Explicit padding to align the 64-bit field that follows.
+    pub __padding2: [u8; 4],
+    pub backupStartPoint: U64<O>,
+    pub backupEndPoint: U64<O>,
+    /// Note this is `bool` in C; it is u8 to allow safe conversions.
+    pub backupEndRequired: u8,
+    /// Explicit padding to align the 32-bit field that follows.
+    pub __padding3: [u8; 3],
+    pub wal_level: U32<O>,
+    /// Note this is `bool` in C; it is u8 to allow safe conversions.
+    pub wal_log_hints: u8,
+    /// Explicit padding to align the 32-bit field that follows.
+    pub __padding4: [u8; 3],
+    pub MaxConnections: U32<O>,
+    pub max_worker_processes: U32<O>,
+    pub max_wal_senders: U32<O>,
+    pub max_prepared_xacts: U32<O>,
+    pub max_locks_per_xact: U32<O>,
+    /// Note this is `bool` in C; it is u8 to allow safe conversions.
+    pub track_commit_timestamp: u8,
+    /// Explicit padding to align the 32-bit field that follows.
+    pub __padding5: [u8; 3],
+    pub maxAlign: U32<O>,
+    pub floatFormat: F64<O>,
+    pub blcksz: U32<O>,
+    pub relseg_size: U32<O>,
+    pub xlog_blcksz: U32<O>,
+    pub xlog_seg_size: U32<O>,
+    pub nameDataLen: U32<O>,
+    pub indexMaxKeys: U32<O>,
+    pub toast_max_chunk_size: U32<O>,
+    pub loblksize: U32<O>,
+    // /// Note this is `bool` in C; it is u8 to allow safe conversions.
+    pub float8ByVal: u8,
+    /// Explicit padding to align the 32-bit field that follows.
+    pub __padding6: [u8; 3],
+    pub data_checksum_version: U32<O>,
+    pub mock_authentication_nonce: [u8; 32],
+    pub crc: U32<O>,
+    /// Explicit padding to align the end of the struct, to satisfy `zerocopy`
+    pub __padding7: [u8; 4],
+}
+
+impl<O: ByteOrder> PortableControlFileData<O> {
+    /// Expected value of `floatFormat` in a valid control file, regardless
+    /// of the writer's endianness: PostgreSQL always writes this specific
+    /// IEEE-754 double to let readers detect float format mismatches.
+    const FLOAT_FORMAT: f64 = 1_000_500.0;
+
+    /// Decode a `PortableControlFileData<O>`, assuming the file was written
+    /// with byte order `O`.
+    ///
+    /// Unlike [`ControlFileData::decode`], this does not depend on the
+    /// reading host's native endianness. Like `decode`, this returns a
+    /// typed `ControlFileError` rather than an ad-hoc anyhow string.
+    pub fn decode_with_order(buf: &[u8]) -> Result<PortableControlFileData<O>, ControlFileError> {
+        let expected_size = std::mem::size_of::<PortableControlFileData<O>>();
+        if buf.len() < expected_size {
+            return Err(ControlFileError::TooShort {
+                expected: expected_size,
+                got: buf.len(),
+            });
+        }
+
+        // `PortableControlFileData` has no alignment requirements beyond 1
+        // (it's built entirely out of byteorder-wrapped types), so unlike
+        // `ControlFileData::decode` there's no alignment check to make
+        // here; size was already checked above, so this cannot fail.
+        let (layout, _remaining) =
+            LayoutVerified::<_, PortableControlFileData<O>>::new_from_prefix(buf)
+                .expect("size was already checked");
+
+        let controlfile = layout.into_ref().clone();
+
+        let data_without_crc = &buf[0..ControlFileData::offsetof_crc()];
+        let expectedcrc = crc32c::crc32c(data_without_crc);
+
+        if expectedcrc != controlfile.crc.get() {
+            return Err(ControlFileError::CrcMismatch {
+                expected: expectedcrc,
+                actual: controlfile.crc.get(),
+            });
+        }
+
+        Ok(controlfile)
+    }
+
+    /// Check whether `buf` looks like a control file written in byte order
+    /// `O`, by checking known-constant fields against their expected
+    /// values. This does not validate the CRC.
+    fn looks_like_order(buf: &[u8]) -> bool {
+        let layout = match LayoutVerified::<_, PortableControlFileData<O>>::new_from_prefix(buf) {
+            Some((layout, _remaining)) => layout,
+            None => return false,
+        };
+        let controlfile = layout.into_ref();
+
+        // `pg_control_version` is a small, strictly positive number in any
+        // real control file; byte-swapping it tends to produce an
+        // implausibly large value. `catalog_version_no` is only checked to
+        // be nonzero.
+        controlfile.pg_control_version.get() > 0
+            && controlfile.pg_control_version.get() < 100_000
+            && controlfile.catalog_version_no.get() > 0
+            && (controlfile.maxAlign.get() == 4 || controlfile.maxAlign.get() == 8)
+            && controlfile.floatFormat.get() == Self::FLOAT_FORMAT
+    }
+}
+
+/// Sniff the byte order that a control file was written with, by checking
+/// known-constant fields (`maxAlign`, `floatFormat`) against both byte
+/// orders. Returns `None` if neither byte order looks plausible, e.g.
+/// because the buffer is corrupt or from an unsupported PostgreSQL
+/// version.
+///
+/// This allows a control file written on one CPU architecture to be
+/// auto-detected and read correctly from a host of a different
+/// architecture, without the caller needing to already know which one
+/// produced it.
+pub fn sniff_byte_order(buf: &[u8]) -> Option<Endianness> {
+    if PortableControlFileData::<LittleEndian>::looks_like_order(buf) {
+        Some(Endianness::Little)
+    } else if PortableControlFileData::<BigEndian>::looks_like_order(buf) {
+        Some(Endianness::Big)
+    } else {
+        None
+    }
+}
+
+/// The byte order a control file was written in, as detected by
+/// [`sniff_byte_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
 }
 
 #[cfg(test)]
@@ -210,4 +881,159 @@ mod tests {
         let cfd_bindgen: &bindgen_bindings::ControlFileData = unsafe { std::mem::transmute(&cfd) };
         assert_eq!(cfd_bindgen.crc, 0x12345678);
     }
+
+    #[test]
+    fn test_encode_zeroes_padding() {
+        // Simulate an instance built by transmuting from the bindgen struct,
+        // whose implicit padding may hold residual/uninitialized bytes.
+        let mut cfd = ControlFileData::default();
+        cfd.__padding1 = [0xAA; 4];
+        cfd.__padding2 = [0xAA; 4];
+        cfd.__padding3 = [0xAA; 3];
+        cfd.__padding4 = [0xAA; 3];
+        cfd.__padding5 = [0xAA; 3];
+        cfd.__padding6 = [0xAA; 3];
+        cfd.__padding7 = [0xAA; 4];
+        cfd.checkPointCopy.__padding1 = [0xAA; 7];
+        cfd.checkPointCopy.__padding4 = [0xAA; 4];
+        cfd.checkPointCopy.__padding5 = [0xAA; 4];
+
+        let encoded = cfd.encode();
+
+        // Round-trip: decoding what we just encoded must succeed, and must
+        // produce a struct with every padding field zeroed.
+        let decoded = ControlFileData::decode(&encoded).unwrap();
+        assert_eq!(decoded.__padding1, [0; 4]);
+        assert_eq!(decoded.__padding2, [0; 4]);
+        assert_eq!(decoded.__padding3, [0; 3]);
+        assert_eq!(decoded.__padding4, [0; 3]);
+        assert_eq!(decoded.__padding5, [0; 3]);
+        assert_eq!(decoded.__padding6, [0; 3]);
+        assert_eq!(decoded.__padding7, [0; 4]);
+        assert_eq!(decoded.checkPointCopy.__padding1, [0; 7]);
+        assert_eq!(decoded.checkPointCopy.__padding4, [0; 4]);
+        assert_eq!(decoded.checkPointCopy.__padding5, [0; 4]);
+
+        // Re-encoding the decoded struct must produce byte-identical output,
+        // since both runs start from all-zero padding.
+        assert_eq!(&*encoded, &*decoded.encode());
+    }
+
+    /// Build a valid, CRC-correct `PortableControlFileData<O>` buffer with
+    /// plausible values for every field `looks_like_order` inspects.
+    fn encode_portable<O: ByteOrder>() -> (PortableControlFileData<O>, Box<[u8]>) {
+        let mut cfd = PortableControlFileData::<O>::default();
+        cfd.pg_control_version = U32::new(1300);
+        cfd.catalog_version_no = U32::new(202301001);
+        cfd.maxAlign = U32::new(8);
+        cfd.floatFormat = F64::new(PortableControlFileData::<O>::FLOAT_FORMAT);
+
+        let crc = crc32c::crc32c(&cfd.as_bytes()[0..ControlFileData::offsetof_crc()]);
+        cfd.crc = U32::new(crc);
+
+        let buf: Box<[u8]> = cfd.as_bytes().into();
+        (cfd, buf)
+    }
+
+    #[test]
+    fn test_portable_control_file_data_matches_native_size() {
+        // `PortableControlFileData` mirrors `ControlFileData` field-for-field
+        // with byte-order-aware wrappers of the same width, so the two must
+        // stay the same size regardless of which `ByteOrder` is chosen.
+        assert_eq!(
+            size_of::<PortableControlFileData<LittleEndian>>(),
+            size_of::<ControlFileData>()
+        );
+        assert_eq!(
+            size_of::<PortableControlFileData<BigEndian>>(),
+            size_of::<ControlFileData>()
+        );
+    }
+
+    #[test]
+    fn test_decode_with_order_round_trips_little_endian() {
+        let (cfd, buf) = encode_portable::<LittleEndian>();
+        let decoded = PortableControlFileData::<LittleEndian>::decode_with_order(&buf).unwrap();
+        assert_eq!(decoded.pg_control_version.get(), cfd.pg_control_version.get());
+        assert_eq!(decoded.catalog_version_no.get(), cfd.catalog_version_no.get());
+        assert_eq!(decoded.crc.get(), cfd.crc.get());
+    }
+
+    #[test]
+    fn test_decode_with_order_round_trips_big_endian() {
+        let (cfd, buf) = encode_portable::<BigEndian>();
+        let decoded = PortableControlFileData::<BigEndian>::decode_with_order(&buf).unwrap();
+        assert_eq!(decoded.pg_control_version.get(), cfd.pg_control_version.get());
+        assert_eq!(decoded.catalog_version_no.get(), cfd.catalog_version_no.get());
+        assert_eq!(decoded.crc.get(), cfd.crc.get());
+    }
+
+    #[test]
+    fn test_decode_with_order_rejects_crc_mismatch() {
+        let (_cfd, mut buf) = encode_portable::<LittleEndian>();
+        // Flip a byte inside the CRC-covered prefix; the trailing bytes are
+        // `__padding7`, which the CRC doesn't cover.
+        buf[0] ^= 0xFF;
+        let err = PortableControlFileData::<LittleEndian>::decode_with_order(&buf).unwrap_err();
+        assert!(matches!(err, ControlFileError::CrcMismatch { .. }));
+    }
+
+    #[test]
+    fn test_sniff_byte_order_detects_little_endian() {
+        let (_cfd, buf) = encode_portable::<LittleEndian>();
+        assert_eq!(sniff_byte_order(&buf), Some(Endianness::Little));
+    }
+
+    #[test]
+    fn test_sniff_byte_order_detects_big_endian() {
+        let (_cfd, buf) = encode_portable::<BigEndian>();
+        assert_eq!(sniff_byte_order(&buf), Some(Endianness::Big));
+    }
+
+    #[test]
+    fn test_sniff_byte_order_rejects_garbage() {
+        let buf = [0u8; PG_CONTROL_FILE_SIZE];
+        assert_eq!(sniff_byte_order(&buf), None);
+    }
+
+    #[test]
+    fn test_versioned_control_file_data_dispatches_to_v16() {
+        let mut cfd = ControlFileData::default();
+        cfd.pg_control_version = v16::PG_CONTROL_VERSION;
+        let buf = cfd.encode();
+
+        match VersionedControlFileData::decode(&buf).unwrap() {
+            VersionedControlFileData::V16(decoded) => {
+                assert_eq!(decoded.pg_control_version, v16::PG_CONTROL_VERSION);
+            }
+            VersionedControlFileData::V15(_) => panic!("expected V16"),
+        }
+    }
+
+    #[test]
+    fn test_versioned_control_file_data_dispatches_to_v15() {
+        let mut cfd = v15::ControlFileData::default();
+        cfd.pg_control_version = v15::PG_CONTROL_VERSION;
+        let buf = cfd.encode();
+
+        match VersionedControlFileData::decode(&buf).unwrap() {
+            VersionedControlFileData::V15(decoded) => {
+                assert_eq!(decoded.pg_control_version, v15::PG_CONTROL_VERSION);
+            }
+            VersionedControlFileData::V16(_) => panic!("expected V15"),
+        }
+    }
+
+    #[test]
+    fn test_versioned_control_file_data_rejects_unknown_version() {
+        let mut cfd = ControlFileData::default();
+        cfd.pg_control_version = 42;
+        let buf = cfd.encode();
+
+        let err = VersionedControlFileData::decode(&buf).unwrap_err();
+        assert!(matches!(
+            err,
+            ControlFileError::UnsupportedVersion { got: 42, .. }
+        ));
+    }
 }